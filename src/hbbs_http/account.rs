@@ -1,7 +1,17 @@
 use super::HbbHttpResponse;
 use hbb_common::{
+    anyhow::anyhow,
+    bail,
     config::{Config, LocalConfig},
-    log, ResultType,
+    log,
+    rand::{self, Rng},
+    sha2::{Digest, Sha256},
+    sodiumoxide::crypto::{box_, sealedbox},
+    ResultType,
+};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet},
+    DecodingKey, Validation,
 };
 use reqwest::blocking::Client;
 use serde::ser::SerializeStruct;
@@ -14,17 +24,74 @@ use std::{
 };
 use url::Url;
 
+// RFC 7636 PKCE. S256 is used whenever possible; `plain` (the raw verifier
+// sent as the challenge) is used only when a discovered provider's
+// `code_challenge_methods_supported` explicitly omits S256.
+const PKCE_VERIFIER_LEN: usize = 64;
+const PKCE_UNRESERVED: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+
+fn gen_code_verifier() -> String {
+    let mut rng = rand::thread_rng();
+    (0..PKCE_VERIFIER_LEN)
+        .map(|_| PKCE_UNRESERVED[rng.gen_range(0..PKCE_UNRESERVED.len())] as char)
+        .collect()
+}
+
+fn code_challenge_s256(verifier: &str) -> String {
+    let digest = Sha256::digest(verifier.as_bytes());
+    base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+}
+
+const NONCE_LEN: usize = 32;
+const JWKS_TTL_SECS: u64 = 60 * 60;
+
+fn gen_nonce() -> String {
+    let mut rng = rand::thread_rng();
+    (0..NONCE_LEN)
+        .map(|_| PKCE_UNRESERVED[rng.gen_range(0..PKCE_UNRESERVED.len())] as char)
+        .collect()
+}
+
 lazy_static::lazy_static! {
     static ref API_SERVER: String = crate::get_api_server(
         Config::get_option("api-server"), Config::get_option("custom-rendezvous-server"));
+    // External, standards-compliant IdP (Keycloak, Authentik, Google, ...). Empty
+    // means "use the fixed /api/oidc/* endpoints on API_SERVER" as before.
+    static ref OIDC_ISSUER: String = Config::get_option("oidc-issuer");
+    // Expected `aud` claim on the id_token. Empty falls back to API_SERVER,
+    // matching the audience the fixed /api/oidc/* endpoints issue for. A
+    // real external IdP (see OIDC_ISSUER) requires this to be set.
+    static ref OIDC_CLIENT_ID: String = Config::get_option("oidc-client-id");
+    // Redirect URI registered with the external IdP. Only used once an
+    // issuer is actually discovered.
+    static ref OIDC_REDIRECT_URI: String = Config::get_option("oidc-redirect-uri");
     static ref OIDC_SESSION: Arc<RwLock<OidcSession>> = Arc::new(RwLock::new(OidcSession::new()));
 }
 
 const QUERY_INTERVAL_SECS: f32 = 1.0;
 const QUERY_TIMEOUT_SECS: u64 = 60 * 3;
+const DISCOVERY_TTL_SECS: u64 = 60 * 60;
+// Refresh this long before the access token actually expires.
+const REFRESH_GRACE_SECS: u64 = 60;
 const REQUESTING_ACCOUNT_AUTH: &str = "Requesting account auth";
 const WAITING_ACCOUNT_AUTH: &str = "Waiting account auth";
 const LOGIN_ACCOUNT_AUTH: &str = "Login account auth";
+const WAITING_DEVICE_AUTH: &str = "Waiting device auth";
+const WAITING_EMAIL_OTP: &str = "Waiting email otp";
+const WAITING_EXTERNAL_AUTH_CODE: &str = "Waiting external auth code";
+
+// "Login with another device": a pending request left unanswered this long
+// is treated as expired by the polling client.
+const DEVICE_AUTH_TIMEOUT_SECS: u64 = 60 * 5;
+const DEVICE_AUTH_QUERY_INTERVAL_SECS: f32 = 1.0;
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct OidcAuthUrl {
@@ -32,6 +99,47 @@ pub struct OidcAuthUrl {
     url: Url,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct DeviceAuthCreated {
+    request_id: String,
+}
+
+/// A pending "login with another device" request, as shown to an
+/// already-trusted device for approval.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingAuthRequest {
+    pub request_id: String,
+    /// The requesting device's uuid, as passed to `create_device_auth_request`.
+    pub uuid: String,
+    pub device_info: DeviceInfo,
+    /// base64-encoded Curve25519 public key generated by the requesting device.
+    pub public_key: String,
+    pub exp: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum DeviceAuthStatus {
+    Pending,
+    Approved { encrypted_session: String },
+    Rejected,
+}
+
+/// Subset of the fields of a `.well-known/openid-configuration` document
+/// (RFC 8414) that the client cares about.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(default)]
+    pub jwks_uri: String,
+    #[serde(default)]
+    pub scopes_supported: Vec<String>,
+    #[serde(default)]
+    pub code_challenge_methods_supported: Vec<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct DeviceInfo {
     /// Linux , Windows , Android ...
@@ -98,10 +206,46 @@ pub struct UserPayload {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthBody {
     pub access_token: String,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    #[serde(default)]
+    pub id_token: Option<String>,
     pub r#type: String,
     pub user: UserPayload,
 }
 
+/// Raw RFC 6749 token endpoint response, as returned by a standards-compliant
+/// external IdP -- unlike `AuthBody`, there's no `HbbHttpResponse` envelope
+/// and no RustDesk-specific `user` payload.
+#[derive(Debug, Deserialize)]
+struct OAuth2TokenResponse {
+    access_token: String,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expires_in: Option<u64>,
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+/// Claims pulled out of a verified `id_token`, so callers can show the
+/// verified email/subject without a second round-trip to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DecodedClaims {
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    pub iss: String,
+    #[serde(default)]
+    pub aud: Option<String>,
+    pub exp: u64,
+    pub iat: u64,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
 pub struct OidcSession {
     client: Client,
     state_msg: &'static str,
@@ -111,6 +255,44 @@ pub struct OidcSession {
     keep_querying: bool,
     running: bool,
     query_timeout: Duration,
+    code_verifier: String,
+    provider_metadata: Option<(OidcProviderMetadata, Instant)>,
+    // resolved once per auth_task run, reused by refresh()
+    token_endpoint: String,
+    // Whether `token_endpoint` above is a real external IdP token endpoint
+    // (RFC 6749 form-encoded request, raw JSON response) rather than the
+    // hbbs `/api/oidc/refresh` endpoint (JSON request, `HbbHttpResponse`).
+    token_endpoint_is_external: bool,
+    access_token_expiry: Option<Instant>,
+    // kept in-memory regardless of `remember_me`; only mirrored to
+    // `LocalConfig` when the user opted in to persistence.
+    access_token: String,
+    refresh_token: String,
+    remember_me: bool,
+    nonce: String,
+    jwks: Option<(JwkSet, Instant)>,
+    claims: Option<DecodedClaims>,
+    untrusted_login: bool,
+    // Second-factor email OTP challenge, set while `auth_task` is blocked
+    // waiting for `submit_otp`.
+    otp_required: bool,
+    otp_error: String,
+    otp_submission: Option<String>,
+    // Authorization code for a real external IdP, handed back once whatever
+    // captures the browser redirect (a platform-specific loopback listener
+    // or deep link handler) calls `submit_external_auth_code`. Keyed by the
+    // `state` value `auth_task` minted, to guard against a stale/mismatched
+    // callback.
+    external_auth_state: String,
+    external_auth_code: Option<(String, String)>,
+    // "Login with another device" sub-flow. Kept separate from the OIDC
+    // fields above since the two flows are independent and can't overlap.
+    device_auth_state_msg: &'static str,
+    device_auth_failed_msg: String,
+    device_auth_body: Option<AuthBody>,
+    device_auth_running: bool,
+    device_auth_keep_querying: bool,
+    device_auth_secret_key: Option<box_::SecretKey>,
 }
 
 #[derive(Serialize)]
@@ -119,6 +301,14 @@ pub struct AuthResult {
     pub failed_msg: String,
     pub url: Option<String>,
     pub auth_body: Option<AuthBody>,
+    pub claims: Option<DecodedClaims>,
+    /// Set once login succeeds; true if this device/IP isn't in the user's
+    /// whitelist and `email_alarm_notification` should fire.
+    pub untrusted_login: bool,
+    /// True while the flow is paused on `WAITING_EMAIL_OTP`, waiting for a
+    /// `submit_otp` call.
+    pub otp_required: bool,
+    pub otp_error: String,
 }
 
 impl serde::Serialize for UserPayload {
@@ -156,24 +346,198 @@ impl OidcSession {
             keep_querying: false,
             running: false,
             query_timeout: Duration::from_secs(QUERY_TIMEOUT_SECS),
+            code_verifier: "".to_owned(),
+            provider_metadata: None,
+            token_endpoint: "".to_owned(),
+            token_endpoint_is_external: false,
+            access_token_expiry: None,
+            access_token: "".to_owned(),
+            refresh_token: "".to_owned(),
+            remember_me: false,
+            nonce: "".to_owned(),
+            jwks: None,
+            claims: None,
+            untrusted_login: false,
+            otp_required: false,
+            otp_error: "".to_owned(),
+            otp_submission: None,
+            external_auth_state: "".to_owned(),
+            external_auth_code: None,
+            device_auth_state_msg: REQUESTING_ACCOUNT_AUTH,
+            device_auth_failed_msg: "".to_owned(),
+            device_auth_body: None,
+            device_auth_running: false,
+            device_auth_keep_querying: false,
+            device_auth_secret_key: None,
         }
     }
 
-    fn auth(op: &str, id: &str, uuid: &str) -> ResultType<HbbHttpResponse<OidcAuthUrl>> {
+    /// Fetches (and caches, with a TTL) the issuer's discovery document, so
+    /// `auth_task` can target a standards-compliant IdP instead of the fixed
+    /// `/api/oidc/*` endpoints.
+    fn discover(issuer: &str) -> ResultType<OidcProviderMetadata> {
+        if let Some((metadata, fetched_at)) = OIDC_SESSION.read().unwrap().provider_metadata.clone()
+        {
+            if fetched_at.elapsed() < Duration::from_secs(DISCOVERY_TTL_SECS) {
+                return Ok(metadata);
+            }
+        }
+        let metadata: OidcProviderMetadata = OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            ))
+            .send()?
+            .json()?;
+        // RFC 8414 / OIDC Discovery 1.0: the document's `issuer` must match
+        // the issuer it was fetched from, or verification could end up bound
+        // to whatever the (possibly spoofed) document claims rather than
+        // the issuer actually configured.
+        if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            bail!(
+                "Discovery document issuer {} does not match configured issuer {}",
+                metadata.issuer,
+                issuer
+            );
+        }
+        OIDC_SESSION.write().unwrap().provider_metadata = Some((metadata.clone(), Instant::now()));
+        Ok(metadata)
+    }
+
+    /// Recomputes the token endpoint (and whether it's a real external IdP
+    /// endpoint) the same way `auth_task` would, for callers like `refresh()`
+    /// that may run in a fresh process with no prior `auth_task` call.
+    fn resolve_token_endpoint() -> (String, bool) {
+        if OIDC_ISSUER.is_empty() {
+            return (format!("{}/api/oidc/refresh", *API_SERVER), false);
+        }
+        match Self::discover(&OIDC_ISSUER) {
+            Ok(metadata) => (metadata.token_endpoint, true),
+            Err(err) => {
+                log::warn!("Failed to discover oidc issuer {}: {}", &*OIDC_ISSUER, err);
+                (format!("{}/api/oidc/refresh", *API_SERVER), false)
+            }
+        }
+    }
+
+    /// Fetches (and caches, with a TTL) the provider's JSON Web Key Set.
+    fn fetch_jwks(jwks_uri: &str) -> ResultType<JwkSet> {
+        if let Some((jwks, fetched_at)) = OIDC_SESSION.read().unwrap().jwks.clone() {
+            if fetched_at.elapsed() < Duration::from_secs(JWKS_TTL_SECS) {
+                return Ok(jwks);
+            }
+        }
+        let jwks: JwkSet = OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .get(jwks_uri)
+            .send()?
+            .json()?;
+        OIDC_SESSION.write().unwrap().jwks = Some((jwks.clone(), Instant::now()));
+        Ok(jwks)
+    }
+
+    /// Verifies the signature, `exp`/`iat`/`iss`/`aud` claims, and `nonce`
+    /// echo of a compact JWS `id_token` against the given JWKS. Always
+    /// requires a real signature check and a matching issuer/audience —
+    /// callers must resolve `jwks_uri` to a concrete JWKS (falling back to
+    /// the API server's own `/api/oidc/jwks` when no discovery took place)
+    /// rather than passing an empty URI, so a spoofed server cannot get
+    /// unverified claims accepted as if they were verified.
+    fn verify_id_token(
+        id_token: &str,
+        jwks_uri: &str,
+        expected_issuer: &str,
+        expected_audience: &str,
+        nonce: &str,
+    ) -> ResultType<DecodedClaims> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        // Never let the untrusted token's own header pick the "expected"
+        // algorithm (the classic alg-confusion attack) -- pin to the
+        // asymmetric algorithms we actually support signature verification
+        // for and reject everything else up front.
+        let mut validation = Validation::new(jsonwebtoken::Algorithm::RS256);
+        validation.algorithms = vec![
+            jsonwebtoken::Algorithm::RS256,
+            jsonwebtoken::Algorithm::ES256,
+        ];
+        validation.set_issuer(&[expected_issuer]);
+        validation.set_audience(&[expected_audience]);
+
+        let jwks = Self::fetch_jwks(jwks_uri)?;
+        let kid = header
+            .kid
+            .as_ref()
+            .ok_or_else(|| anyhow!("id_token header is missing kid"))?;
+        let jwk = jwks
+            .find(kid)
+            .ok_or_else(|| anyhow!("No matching jwk for kid {}", kid))?;
+        let decoding_key = match &jwk.algorithm {
+            AlgorithmParameters::RSA(rsa) => DecodingKey::from_rsa_components(&rsa.n, &rsa.e)?,
+            AlgorithmParameters::EllipticCurve(ec) => {
+                DecodingKey::from_ec_components(&ec.x, &ec.y)?
+            }
+            _ => bail!("Unsupported jwk algorithm for kid {}", kid),
+        };
+        let claims =
+            jsonwebtoken::decode::<DecodedClaims>(id_token, &decoding_key, &validation)?.claims;
+
+        // jsonwebtoken doesn't check `iat` for us; reject tokens claiming to
+        // have been issued in the future (beyond a little clock skew).
+        if claims.iat > now_secs() + 60 {
+            bail!("id_token iat is in the future");
+        }
+        if claims.nonce.as_deref() != Some(nonce) {
+            bail!("id_token nonce mismatch, possible replay");
+        }
+        Ok(claims)
+    }
+
+    fn auth(
+        op: &str,
+        id: &str,
+        uuid: &str,
+        code_challenge: &str,
+        code_challenge_method: &str,
+        nonce: &str,
+        endpoint: &str,
+    ) -> ResultType<HbbHttpResponse<OidcAuthUrl>> {
         Ok(OIDC_SESSION
             .read()
             .unwrap()
             .client
-            .post(format!("{}/api/oidc/auth", *API_SERVER))
-            .json(&HashMap::from([("op", op), ("id", id), ("uuid", uuid)]))
+            .post(endpoint)
+            .json(&HashMap::from([
+                ("op", op),
+                ("id", id),
+                ("uuid", uuid),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", code_challenge_method),
+                ("nonce", nonce),
+            ]))
             .send()?
             .try_into()?)
     }
 
-    fn query(code: &str, id: &str, uuid: &str) -> ResultType<HbbHttpResponse<AuthBody>> {
+    fn query(
+        code: &str,
+        id: &str,
+        uuid: &str,
+        code_verifier: &str,
+        endpoint: &str,
+    ) -> ResultType<HbbHttpResponse<AuthBody>> {
         let url = reqwest::Url::parse_with_params(
-            &format!("{}/api/oidc/auth-query", *API_SERVER),
-            &[("code", code), ("id", id), ("uuid", uuid)],
+            endpoint,
+            &[
+                ("code", code),
+                ("id", id),
+                ("uuid", uuid),
+                ("code_verifier", code_verifier),
+            ],
         )?;
         Ok(OIDC_SESSION
             .read()
@@ -191,6 +555,15 @@ impl OidcSession {
         self.running = false;
         self.code_url = None;
         self.auth_body = None;
+        self.code_verifier = "".to_owned();
+        self.nonce = "".to_owned();
+        self.claims = None;
+        self.untrusted_login = false;
+        self.otp_required = false;
+        self.otp_error = "".to_owned();
+        self.otp_submission = None;
+        self.external_auth_state = "".to_owned();
+        self.external_auth_code = None;
     }
 
     fn before_task(&mut self) {
@@ -207,7 +580,70 @@ impl OidcSession {
     }
 
     fn auth_task(op: String, id: String, uuid: String, remember_me: bool) {
-        let auth_request_res = Self::auth(&op, &id, &uuid);
+        let code_verifier = gen_code_verifier();
+        let nonce = gen_nonce();
+        OIDC_SESSION.write().unwrap().code_verifier = code_verifier.clone();
+        OIDC_SESSION.write().unwrap().nonce = nonce.clone();
+
+        let metadata = if OIDC_ISSUER.is_empty() {
+            None
+        } else {
+            match Self::discover(&OIDC_ISSUER) {
+                Ok(metadata) => Some(metadata),
+                Err(err) => {
+                    log::warn!("Failed to discover oidc issuer {}: {}", &*OIDC_ISSUER, err);
+                    None
+                }
+            }
+        };
+
+        match metadata {
+            // A standards-compliant external IdP was actually discovered:
+            // speak real OAuth2/OIDC to it rather than the fixed,
+            // RustDesk-specific /api/oidc/* shim.
+            Some(metadata) => {
+                Self::auth_task_external(metadata, id, uuid, remember_me, code_verifier, nonce)
+            }
+            None => Self::auth_task_custom(op, id, uuid, remember_me, code_verifier, nonce),
+        }
+    }
+
+    /// The original, RustDesk-specific flow: the client POSTs JSON to hbbs'
+    /// fixed `/api/oidc/*` endpoints, which in turn talk to whatever IdP is
+    /// configured server-side and hands back our own `HbbHttpResponse`
+    /// envelope.
+    fn auth_task_custom(
+        op: String,
+        id: String,
+        uuid: String,
+        remember_me: bool,
+        code_verifier: String,
+        nonce: String,
+    ) {
+        let code_challenge = code_challenge_s256(&code_verifier);
+        let auth_endpoint = format!("{}/api/oidc/auth", *API_SERVER);
+        let query_endpoint = format!("{}/api/oidc/auth-query", *API_SERVER);
+        let refresh_endpoint = format!("{}/api/oidc/refresh", *API_SERVER);
+        OIDC_SESSION.write().unwrap().token_endpoint = refresh_endpoint;
+        OIDC_SESSION.write().unwrap().token_endpoint_is_external = false;
+
+        let jwks_uri = format!("{}/api/oidc/jwks", *API_SERVER);
+        let expected_issuer = API_SERVER.clone();
+        let expected_audience = if OIDC_CLIENT_ID.is_empty() {
+            API_SERVER.clone()
+        } else {
+            OIDC_CLIENT_ID.clone()
+        };
+
+        let auth_request_res = Self::auth(
+            &op,
+            &id,
+            &uuid,
+            &code_challenge,
+            "S256",
+            &nonce,
+            &auth_endpoint,
+        );
         log::info!("Request oidc auth result: {:?}", &auth_request_res);
         let code_url = match auth_request_res {
             Ok(HbbHttpResponse::<_>::Data(code_url)) => code_url,
@@ -240,16 +676,31 @@ impl OidcSession {
             .set_state(WAITING_ACCOUNT_AUTH, "".to_owned());
         OIDC_SESSION.write().unwrap().code_url = Some(code_url.clone());
 
-        let begin = Instant::now();
+        let mut begin = Instant::now();
         let query_timeout = OIDC_SESSION.read().unwrap().query_timeout;
         while OIDC_SESSION.read().unwrap().keep_querying && begin.elapsed() < query_timeout {
-            match Self::query(&code_url.code, &id, &uuid) {
+            match Self::query(&code_url.code, &id, &uuid, &code_verifier, &query_endpoint) {
                 Ok(HbbHttpResponse::<_>::Data(mut auth_body)) => {
+                    if let Some(id_token) = &auth_body.id_token {
+                        match Self::verify_id_token(
+                            id_token,
+                            &jwks_uri,
+                            &expected_issuer,
+                            &expected_audience,
+                            &nonce,
+                        ) {
+                            Ok(claims) => OIDC_SESSION.write().unwrap().claims = Some(claims),
+                            Err(err) => {
+                                OIDC_SESSION
+                                    .write()
+                                    .unwrap()
+                                    .set_state(WAITING_ACCOUNT_AUTH, err.to_string());
+                                return;
+                            }
+                        }
+                    }
+                    Self::store_tokens(&auth_body, remember_me);
                     if remember_me {
-                        LocalConfig::set_option(
-                            "access_token".to_owned(),
-                            auth_body.access_token.clone(),
-                        );
                         auth_body.user.ser_store_local = true;
                         LocalConfig::set_option(
                             "user_info".to_owned(),
@@ -257,6 +708,9 @@ impl OidcSession {
                         );
                         auth_body.user.ser_store_local = false;
                     }
+                    let untrusted_login =
+                        Self::should_notify_untrusted_login(&auth_body.user, &uuid);
+                    OIDC_SESSION.write().unwrap().untrusted_login = untrusted_login;
                     OIDC_SESSION
                         .write()
                         .unwrap()
@@ -267,6 +721,20 @@ impl OidcSession {
                 Ok(HbbHttpResponse::<_>::Error(err)) => {
                     if err.contains("No authed oidc is found") {
                         // ignore, keep querying
+                    } else if err.contains("verification required") {
+                        if !Self::wait_for_otp(&id, &uuid) {
+                            // keep_querying was cleared while we waited
+                            return;
+                        }
+                        // `wait_for_otp` can block far longer than
+                        // `query_timeout` (the user has to check their
+                        // email); restart the clock so that time isn't
+                        // counted against the overall auth timeout.
+                        begin = Instant::now();
+                        OIDC_SESSION
+                            .write()
+                            .unwrap()
+                            .set_state(WAITING_ACCOUNT_AUTH, "".to_owned());
                     } else {
                         OIDC_SESSION
                             .write()
@@ -296,11 +764,348 @@ impl OidcSession {
         // no need to handle "keep_querying == false"
     }
 
+    /// The standards-compliant flow for a discovered external IdP: a real
+    /// browser-facing authorization request (GET redirect, not a JSON POST)
+    /// and an RFC 6749 form-encoded token exchange against the raw OAuth2
+    /// JSON response, instead of reusing hbbs' custom API shape against
+    /// endpoints that don't speak it.
+    fn auth_task_external(
+        metadata: OidcProviderMetadata,
+        id: String,
+        uuid: String,
+        remember_me: bool,
+        code_verifier: String,
+        nonce: String,
+    ) {
+        if OIDC_CLIENT_ID.is_empty() {
+            OIDC_SESSION.write().unwrap().set_state(
+                REQUESTING_ACCOUNT_AUTH,
+                "oidc-client-id must be configured for an external issuer".to_owned(),
+            );
+            return;
+        }
+        if OIDC_REDIRECT_URI.is_empty() {
+            OIDC_SESSION.write().unwrap().set_state(
+                REQUESTING_ACCOUNT_AUTH,
+                "oidc-redirect-uri must be configured for an external issuer".to_owned(),
+            );
+            return;
+        }
+        if metadata.jwks_uri.is_empty() {
+            OIDC_SESSION.write().unwrap().set_state(
+                REQUESTING_ACCOUNT_AUTH,
+                "Discovered issuer did not advertise a jwks_uri".to_owned(),
+            );
+            return;
+        }
+
+        // Only fall back to `plain` when the provider explicitly excludes
+        // S256 from its advertised methods.
+        let use_plain = !metadata.code_challenge_methods_supported.is_empty()
+            && !metadata
+                .code_challenge_methods_supported
+                .iter()
+                .any(|method| method == "S256");
+        let (code_challenge, code_challenge_method) = if use_plain {
+            (code_verifier.clone(), "plain")
+        } else {
+            (code_challenge_s256(&code_verifier), "S256")
+        };
+
+        let state = gen_nonce();
+        let url = match Self::external_auth_url(
+            &metadata.authorization_endpoint,
+            &*OIDC_CLIENT_ID,
+            &*OIDC_REDIRECT_URI,
+            &state,
+            &nonce,
+            &code_challenge,
+            code_challenge_method,
+            &metadata.scopes_supported,
+        ) {
+            Ok(url) => url,
+            Err(err) => {
+                OIDC_SESSION
+                    .write()
+                    .unwrap()
+                    .set_state(REQUESTING_ACCOUNT_AUTH, err.to_string());
+                return;
+            }
+        };
+
+        {
+            let mut session = OIDC_SESSION.write().unwrap();
+            session.token_endpoint = metadata.token_endpoint.clone();
+            session.token_endpoint_is_external = true;
+            session.external_auth_state = state.clone();
+            session.external_auth_code = None;
+            session.code_url = Some(OidcAuthUrl {
+                code: state.clone(),
+                url,
+            });
+            session.set_state(WAITING_EXTERNAL_AUTH_CODE, "".to_owned());
+        }
+
+        let begin = Instant::now();
+        let query_timeout = OIDC_SESSION.read().unwrap().query_timeout;
+        let code = loop {
+            if !OIDC_SESSION.read().unwrap().keep_querying {
+                return;
+            }
+            if begin.elapsed() >= query_timeout {
+                OIDC_SESSION
+                    .write()
+                    .unwrap()
+                    .set_state(WAITING_ACCOUNT_AUTH, "timeout".to_owned());
+                return;
+            }
+            let submission = OIDC_SESSION.write().unwrap().external_auth_code.take();
+            match submission {
+                Some((submitted_state, code)) if submitted_state == state => break code,
+                Some(_) => {
+                    // Stale callback for a previous attempt; ignore.
+                }
+                None => {}
+            }
+            Self::sleep(QUERY_INTERVAL_SECS);
+        };
+
+        let token = match Self::exchange_code_for_token(
+            &metadata.token_endpoint,
+            &code,
+            &*OIDC_CLIENT_ID,
+            &*OIDC_REDIRECT_URI,
+            &code_verifier,
+        ) {
+            Ok(token) => token,
+            Err(err) => {
+                OIDC_SESSION
+                    .write()
+                    .unwrap()
+                    .set_state(WAITING_ACCOUNT_AUTH, err.to_string());
+                return;
+            }
+        };
+
+        let id_token = match &token.id_token {
+            Some(id_token) => id_token,
+            None => {
+                OIDC_SESSION.write().unwrap().set_state(
+                    WAITING_ACCOUNT_AUTH,
+                    "Token response is missing id_token".to_owned(),
+                );
+                return;
+            }
+        };
+        let expected_audience = OIDC_CLIENT_ID.clone();
+        let claims = match Self::verify_id_token(
+            id_token,
+            &metadata.jwks_uri,
+            &metadata.issuer,
+            &expected_audience,
+            &nonce,
+        ) {
+            Ok(claims) => claims,
+            Err(err) => {
+                OIDC_SESSION
+                    .write()
+                    .unwrap()
+                    .set_state(WAITING_ACCOUNT_AUTH, err.to_string());
+                return;
+            }
+        };
+
+        let mut auth_body = AuthBody {
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_in: token.expires_in,
+            id_token: Some(id_token.clone()),
+            r#type: "Bearer".to_owned(),
+            user: Self::user_from_claims(&claims),
+        };
+        OIDC_SESSION.write().unwrap().claims = Some(claims);
+        Self::store_tokens(&auth_body, remember_me);
+        if remember_me {
+            auth_body.user.ser_store_local = true;
+            LocalConfig::set_option(
+                "user_info".to_owned(),
+                serde_json::to_string(&auth_body.user).unwrap_or_default(),
+            );
+            auth_body.user.ser_store_local = false;
+        }
+        let untrusted_login = Self::should_notify_untrusted_login(&auth_body.user, &uuid);
+        let mut session = OIDC_SESSION.write().unwrap();
+        session.untrusted_login = untrusted_login;
+        session.set_state(LOGIN_ACCOUNT_AUTH, "".to_owned());
+        session.auth_body = Some(auth_body);
+    }
+
+    /// Builds the browser-facing authorization request URL (RFC 6749
+    /// section 4.1.1) -- this is navigated to directly, never POSTed to.
+    #[allow(clippy::too_many_arguments)]
+    fn external_auth_url(
+        auth_endpoint: &str,
+        client_id: &str,
+        redirect_uri: &str,
+        state: &str,
+        nonce: &str,
+        code_challenge: &str,
+        code_challenge_method: &str,
+        scopes: &[String],
+    ) -> ResultType<Url> {
+        let scope = if scopes.is_empty() {
+            "openid email".to_owned()
+        } else {
+            scopes.join(" ")
+        };
+        Ok(Url::parse_with_params(
+            auth_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", client_id),
+                ("redirect_uri", redirect_uri),
+                ("scope", scope.as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", code_challenge_method),
+            ],
+        )?)
+    }
+
+    /// Exchanges an authorization code for tokens using a real RFC 6749
+    /// form-encoded request, parsing the raw OAuth2 JSON response -- not
+    /// `HbbHttpResponse`'s `{data: ...}` / `{error: ...}` envelope.
+    fn exchange_code_for_token(
+        token_endpoint: &str,
+        code: &str,
+        client_id: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> ResultType<OAuth2TokenResponse> {
+        let resp = OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", client_id),
+                ("code_verifier", code_verifier),
+            ])
+            .send()?;
+        Self::parse_token_response(resp)
+    }
+
+    fn parse_token_response(resp: reqwest::blocking::Response) -> ResultType<OAuth2TokenResponse> {
+        if !resp.status().is_success() {
+            let err: serde_json::Value = resp.json().unwrap_or_default();
+            let error = err.get("error").and_then(|v| v.as_str());
+            let description = err.get("error_description").and_then(|v| v.as_str());
+            let msg = match (error, description) {
+                (Some(error), Some(description)) => format!("{}: {}", error, description),
+                (Some(error), None) => error.to_owned(),
+                (None, Some(description)) => description.to_owned(),
+                (None, None) => "token request failed".to_owned(),
+            };
+            bail!(msg);
+        }
+        Ok(resp.json()?)
+    }
+
+    /// A real external IdP has no notion of RustDesk's `UserPayload`; we
+    /// treat the verified id_token claims as the source of truth for who
+    /// logged in instead of a second, RustDesk-specific user lookup.
+    fn user_from_claims(claims: &DecodedClaims) -> UserPayload {
+        UserPayload {
+            name: claims.email.clone().unwrap_or_else(|| claims.sub.clone()),
+            email: claims.email.clone(),
+            note: None,
+            status: UserStatus::Normal,
+            info: UserInfo::default(),
+            is_admin: false,
+            third_auth_type: Some("oidc".to_owned()),
+            ser_store_local: false,
+        }
+    }
+
     fn set_state(&mut self, state_msg: &'static str, failed_msg: String) {
         self.state_msg = state_msg;
         self.failed_msg = failed_msg;
     }
 
+    /// Blocks `auth_task` on `WAITING_EMAIL_OTP`, retrying `submit_otp`
+    /// submissions against the server until one is accepted. Returns `false`
+    /// if the auth was cancelled while waiting.
+    fn wait_for_otp(id: &str, uuid: &str) -> bool {
+        {
+            let mut session = OIDC_SESSION.write().unwrap();
+            session.otp_required = true;
+            session.otp_error = "".to_owned();
+            session.set_state(WAITING_EMAIL_OTP, "".to_owned());
+        }
+        loop {
+            if !OIDC_SESSION.read().unwrap().keep_querying {
+                return false;
+            }
+            let submission = OIDC_SESSION.write().unwrap().otp_submission.take();
+            if let Some(code) = submission {
+                match Self::verify_otp(id, uuid, &code) {
+                    Ok(()) => {
+                        OIDC_SESSION.write().unwrap().otp_required = false;
+                        return true;
+                    }
+                    Err(err) => {
+                        OIDC_SESSION.write().unwrap().otp_error = err.to_string();
+                    }
+                }
+            }
+            Self::sleep(QUERY_INTERVAL_SECS);
+        }
+    }
+
+    fn verify_otp(id: &str, uuid: &str, code: &str) -> ResultType<()> {
+        let res: HbbHttpResponse<serde_json::Value> = OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(format!("{}/api/oidc/verify-otp", *API_SERVER))
+            .json(&HashMap::from([("id", id), ("uuid", uuid), ("code", code)]))
+            .send()?
+            .try_into()?;
+        match res {
+            HbbHttpResponse::<_>::Data(_) => Ok(()),
+            HbbHttpResponse::<_>::Error(err) => bail!(err),
+            _ => bail!("Invalid otp verification response"),
+        }
+    }
+
+    /// Submits the code the user typed for the email-OTP second factor.
+    /// `auth_task` picks it up and resumes polling once the server accepts it.
+    pub fn submit_otp(code: String) -> ResultType<()> {
+        if !OIDC_SESSION.read().unwrap().otp_required {
+            bail!("Not waiting for an email otp code");
+        }
+        OIDC_SESSION.write().unwrap().otp_submission = Some(code);
+        Ok(())
+    }
+
+    /// Hands the authorization `code` captured from an external IdP's
+    /// browser redirect back to `auth_task_external`, which picks it up and
+    /// performs the token exchange. `state` must match the one minted for
+    /// the current attempt (returned as `code_url.code`/`AuthResult::url`'s
+    /// `state` query param), guarding against stale or forged callbacks.
+    pub fn submit_external_auth_code(state: String, code: String) -> ResultType<()> {
+        let current_state = OIDC_SESSION.read().unwrap().external_auth_state.clone();
+        if current_state.is_empty() || current_state != state {
+            bail!("Not waiting for this external auth state");
+        }
+        OIDC_SESSION.write().unwrap().external_auth_code = Some((state, code));
+        Ok(())
+    }
+
     fn wait_stop_querying() {
         let wait_secs = 0.3;
         while OIDC_SESSION.read().unwrap().running {
@@ -324,6 +1129,10 @@ impl OidcSession {
             failed_msg: self.failed_msg.clone(),
             url: self.code_url.as_ref().map(|x| x.url.to_string()),
             auth_body: self.auth_body.clone(),
+            claims: self.claims.clone(),
+            untrusted_login: self.untrusted_login,
+            otp_required: self.otp_required,
+            otp_error: self.otp_error.clone(),
         }
     }
 
@@ -334,4 +1143,502 @@ impl OidcSession {
     pub fn get_result() -> AuthResult {
         OIDC_SESSION.read().unwrap().get_result_()
     }
+
+    /// Starts a "login with another device" request: generates an ephemeral
+    /// Curve25519 keypair, asks the server to page a trusted device, and
+    /// polls for the approval. The session secret never reaches the server
+    /// in plaintext -- it is decrypted locally with the keypair's secret key.
+    pub fn login_with_device(id: String, uuid: String, device_info: DeviceInfo) {
+        OIDC_SESSION.write().unwrap().device_auth_keep_querying = false;
+        while OIDC_SESSION.read().unwrap().device_auth_running {
+            Self::sleep(0.3);
+        }
+        {
+            let mut session = OIDC_SESSION.write().unwrap();
+            session.device_auth_state_msg = REQUESTING_ACCOUNT_AUTH;
+            session.device_auth_failed_msg = "".to_owned();
+            session.device_auth_body = None;
+            session.device_auth_keep_querying = true;
+            session.device_auth_running = true;
+        }
+        std::thread::spawn(move || {
+            Self::device_auth_task(id, uuid, device_info);
+            OIDC_SESSION.write().unwrap().device_auth_running = false;
+        });
+    }
+
+    fn create_device_auth_request(
+        id: &str,
+        uuid: &str,
+        device_info: &DeviceInfo,
+        public_key: &str,
+    ) -> ResultType<HbbHttpResponse<DeviceAuthCreated>> {
+        Ok(OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(format!("{}/api/auth-request", *API_SERVER))
+            .json(&serde_json::json!({
+                "id": id,
+                "uuid": uuid,
+                "device_info": device_info,
+                "public_key": public_key,
+            }))
+            .send()?
+            .try_into()?)
+    }
+
+    fn query_device_auth_status(request_id: &str) -> ResultType<DeviceAuthStatus> {
+        Ok(OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .get(format!(
+                "{}/api/auth-request-query?request_id={}",
+                *API_SERVER, request_id
+            ))
+            .send()?
+            .json()?)
+    }
+
+    fn device_auth_task(id: String, uuid: String, device_info: DeviceInfo) {
+        let (pk, sk) = box_::gen_keypair();
+        OIDC_SESSION.write().unwrap().device_auth_secret_key = Some(sk.clone());
+        let public_key = base64::encode(pk.0);
+
+        let created = Self::create_device_auth_request(&id, &uuid, &device_info, &public_key);
+        let request_id = match created {
+            Ok(HbbHttpResponse::<_>::Data(created)) => created.request_id,
+            Ok(HbbHttpResponse::<_>::Error(err)) => {
+                OIDC_SESSION
+                    .write()
+                    .unwrap()
+                    .set_device_auth_state(REQUESTING_ACCOUNT_AUTH, err);
+                return;
+            }
+            _ => {
+                OIDC_SESSION.write().unwrap().set_device_auth_state(
+                    REQUESTING_ACCOUNT_AUTH,
+                    "Invalid auth-request response".to_owned(),
+                );
+                return;
+            }
+        };
+
+        OIDC_SESSION
+            .write()
+            .unwrap()
+            .set_device_auth_state(WAITING_DEVICE_AUTH, "".to_owned());
+
+        let begin = Instant::now();
+        let timeout = Duration::from_secs(DEVICE_AUTH_TIMEOUT_SECS);
+        while OIDC_SESSION.read().unwrap().device_auth_keep_querying && begin.elapsed() < timeout {
+            match Self::query_device_auth_status(&request_id) {
+                Ok(DeviceAuthStatus::Pending) => {
+                    // keep polling
+                }
+                Ok(DeviceAuthStatus::Approved { encrypted_session }) => {
+                    match Self::decrypt_session(&encrypted_session, &pk, &sk) {
+                        Ok(mut auth_body) => {
+                            Self::store_tokens(&auth_body, true);
+                            auth_body.user.ser_store_local = true;
+                            LocalConfig::set_option(
+                                "user_info".to_owned(),
+                                serde_json::to_string(&auth_body.user).unwrap_or_default(),
+                            );
+                            auth_body.user.ser_store_local = false;
+                            let untrusted_login =
+                                Self::should_notify_untrusted_login(&auth_body.user, &uuid);
+                            let mut session = OIDC_SESSION.write().unwrap();
+                            session.untrusted_login = untrusted_login;
+                            session.set_device_auth_state(LOGIN_ACCOUNT_AUTH, "".to_owned());
+                            session.device_auth_body = Some(auth_body);
+                        }
+                        Err(err) => {
+                            OIDC_SESSION
+                                .write()
+                                .unwrap()
+                                .set_device_auth_state(REQUESTING_ACCOUNT_AUTH, err.to_string());
+                        }
+                    }
+                    return;
+                }
+                Ok(DeviceAuthStatus::Rejected) => {
+                    OIDC_SESSION.write().unwrap().set_device_auth_state(
+                        REQUESTING_ACCOUNT_AUTH,
+                        "Login request was rejected".to_owned(),
+                    );
+                    return;
+                }
+                Err(err) => {
+                    log::trace!("Failed to query auth-request {}", err);
+                    // ignore, keep querying
+                }
+            }
+            Self::sleep(DEVICE_AUTH_QUERY_INTERVAL_SECS);
+        }
+
+        if begin.elapsed() >= timeout {
+            OIDC_SESSION
+                .write()
+                .unwrap()
+                .set_device_auth_state(WAITING_DEVICE_AUTH, "timeout".to_owned());
+        }
+    }
+
+    fn decrypt_session(
+        encrypted_session_b64: &str,
+        pk: &box_::PublicKey,
+        sk: &box_::SecretKey,
+    ) -> ResultType<AuthBody> {
+        let encrypted = base64::decode(encrypted_session_b64)?;
+        let decrypted = sealedbox::open(&encrypted, pk, sk)
+            .map_err(|_| anyhow!("Failed to decrypt session, wrong key or corrupted payload"))?;
+        Ok(serde_json::from_slice(&decrypted)?)
+    }
+
+    fn set_device_auth_state(&mut self, state_msg: &'static str, failed_msg: String) {
+        self.device_auth_state_msg = state_msg;
+        self.device_auth_failed_msg = failed_msg;
+    }
+
+    pub fn device_auth_cancel() {
+        OIDC_SESSION.write().unwrap().device_auth_keep_querying = false;
+    }
+
+    pub fn device_auth_result() -> AuthResult {
+        let session = OIDC_SESSION.read().unwrap();
+        AuthResult {
+            state_msg: session.device_auth_state_msg.to_string(),
+            failed_msg: session.device_auth_failed_msg.clone(),
+            url: None,
+            auth_body: session.device_auth_body.clone(),
+            claims: None,
+            untrusted_login: session.untrusted_login,
+            otp_required: false,
+            otp_error: "".to_owned(),
+        }
+    }
+
+    /// Lists this account's pending "login with another device" requests, for
+    /// an already-trusted device to approve or reject.
+    pub fn list_pending_device_auth_requests() -> ResultType<Vec<PendingAuthRequest>> {
+        let access_token = Self::valid_access_token()?;
+        Ok(OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .get(format!("{}/api/auth-request/list", *API_SERVER))
+            .bearer_auth(access_token)
+            .send()?
+            .json()?)
+    }
+
+    /// Approves a pending request: encrypts the current session to the
+    /// requesting device's public key (so the server only ever sees
+    /// ciphertext) and whitelists the device for `ttl_secs`.
+    pub fn approve_device_auth_request(
+        request_id: &str,
+        device_uuid: &str,
+        device_public_key_b64: &str,
+        device_info: DeviceInfo,
+        ttl_secs: u64,
+    ) -> ResultType<()> {
+        let access_token = Self::valid_access_token()?;
+        let auth_body = OIDC_SESSION
+            .read()
+            .unwrap()
+            .auth_body
+            .clone()
+            .ok_or_else(|| anyhow!("No active session to share"))?;
+        let device_pk_bytes = base64::decode(device_public_key_b64)?;
+        let device_pk = box_::PublicKey::from_slice(&device_pk_bytes)
+            .ok_or_else(|| anyhow!("Invalid device public key"))?;
+        let encrypted_session = base64::encode(sealedbox::seal(
+            &serde_json::to_vec(&auth_body)?,
+            &device_pk,
+        ));
+        // `data` must be the device uuid/ip, matching what
+        // `should_notify_untrusted_login` and `add_whitelist_entry` key off
+        // of -- not the one-shot device-auth public key, which is never seen
+        // again once this request is approved.
+        let whitelist_entry = WhitelistItem {
+            data: device_uuid.to_owned(),
+            info: device_info,
+            exp: now_secs() + ttl_secs,
+        };
+
+        OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(format!("{}/api/auth-request/approve", *API_SERVER))
+            .bearer_auth(access_token)
+            .json(&serde_json::json!({
+                "request_id": request_id,
+                "encrypted_session": encrypted_session,
+                "whitelist": whitelist_entry,
+            }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub fn reject_device_auth_request(request_id: &str) -> ResultType<()> {
+        let access_token = Self::valid_access_token()?;
+        OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(format!("{}/api/auth-request/reject", *API_SERVER))
+            .bearer_auth(access_token)
+            .json(&HashMap::from([("request_id", request_id)]))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Lists the account's trusted devices, dropping entries whose `exp` has
+    /// already passed rather than trusting the server to have pruned them.
+    pub fn list_whitelist() -> ResultType<Vec<WhitelistItem>> {
+        let access_token = Self::valid_access_token()?;
+        let items: Vec<WhitelistItem> = OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .get(format!("{}/api/whitelist", *API_SERVER))
+            .bearer_auth(access_token)
+            .send()?
+            .json()?;
+        let now = now_secs();
+        Ok(items.into_iter().filter(|item| item.exp > now).collect())
+    }
+
+    pub fn add_whitelist_entry(data: String, info: DeviceInfo, ttl_secs: u64) -> ResultType<()> {
+        let access_token = Self::valid_access_token()?;
+        let entry = WhitelistItem {
+            data,
+            info,
+            exp: now_secs() + ttl_secs,
+        };
+        OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(format!("{}/api/whitelist", *API_SERVER))
+            .bearer_auth(access_token)
+            .json(&entry)
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub fn remove_whitelist_entry(data: &str) -> ResultType<()> {
+        let access_token = Self::valid_access_token()?;
+        OIDC_SESSION
+            .read()
+            .unwrap()
+            .client
+            .post(format!("{}/api/whitelist/remove", *API_SERVER))
+            .bearer_auth(access_token)
+            .json(&HashMap::from([("data", data)]))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Whether a login from `device_data` (ip / device uuid) should trigger
+    /// the `email_alarm_notification` path: the user has it turned on, and
+    /// this device isn't among their current (non-expired) whitelist entries.
+    pub fn should_notify_untrusted_login(user: &UserPayload, device_data: &str) -> bool {
+        if !user.info.settings.email_alarm_notification {
+            return false;
+        }
+        let now = now_secs();
+        !user
+            .info
+            .login_device_whitelist
+            .iter()
+            .any(|item| item.data == device_data && item.exp > now)
+    }
+
+    fn store_tokens(auth_body: &AuthBody, remember_me: bool) {
+        let expiry = auth_body
+            .expires_in
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        // Kept in-memory unconditionally so a session that didn't opt into
+        // "remember me" can still refresh/use its access token for the rest
+        // of the run; only persisted to `LocalConfig` when the user asked
+        // for it to survive a restart.
+        {
+            let mut session = OIDC_SESSION.write().unwrap();
+            session.access_token_expiry = expiry;
+            session.access_token = auth_body.access_token.clone();
+            session.refresh_token = auth_body.refresh_token.clone().unwrap_or_default();
+            session.remember_me = remember_me;
+        }
+        if remember_me {
+            LocalConfig::set_option("access_token".to_owned(), auth_body.access_token.clone());
+            LocalConfig::set_option(
+                "refresh_token".to_owned(),
+                auth_body.refresh_token.clone().unwrap_or_default(),
+            );
+        }
+    }
+
+    /// Exchanges the stored refresh token for a new access token, rotating
+    /// the refresh token if the server returns one. On `invalid_grant` the
+    /// stored credentials are dropped and the session goes back to asking
+    /// for a fresh login.
+    pub fn refresh() -> ResultType<()> {
+        let (refresh_token, remember_me) = {
+            let session = OIDC_SESSION.read().unwrap();
+            (session.refresh_token.clone(), session.remember_me)
+        };
+        let refresh_token = if refresh_token.is_empty() {
+            LocalConfig::get_option("refresh_token")
+        } else {
+            refresh_token
+        };
+        if refresh_token.is_empty() {
+            bail!("No refresh token stored");
+        }
+        // `token_endpoint` is only populated by a prior `auth_task` call in
+        // this process; on a fresh restart with a remembered refresh token
+        // it's still "". Recompute it the same way `auth_task` would rather
+        // than posting to an empty URL.
+        let (endpoint, is_external) = {
+            let session = OIDC_SESSION.read().unwrap();
+            (
+                session.token_endpoint.clone(),
+                session.token_endpoint_is_external,
+            )
+        };
+        let (endpoint, is_external) = if endpoint.is_empty() {
+            let resolved = Self::resolve_token_endpoint();
+            let mut session = OIDC_SESSION.write().unwrap();
+            session.token_endpoint = resolved.0.clone();
+            session.token_endpoint_is_external = resolved.1;
+            resolved
+        } else {
+            (endpoint, is_external)
+        };
+        if is_external {
+            // Real IdP: RFC 6749 form-encoded request, raw OAuth2 JSON
+            // response, no RustDesk `user` payload to carry over.
+            let resp = OIDC_SESSION
+                .read()
+                .unwrap()
+                .client
+                .post(&endpoint)
+                .form(&[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &refresh_token),
+                ])
+                .send()?;
+            match Self::parse_token_response(resp) {
+                Ok(token) => {
+                    let session = OIDC_SESSION.read().unwrap();
+                    let user = session
+                        .auth_body
+                        .as_ref()
+                        .map(|b| b.user.clone())
+                        .or_else(|| session.claims.as_ref().map(Self::user_from_claims));
+                    drop(session);
+                    let user = match user {
+                        Some(user) => user,
+                        None => bail!("No user identity available to refresh"),
+                    };
+                    let auth_body = AuthBody {
+                        access_token: token.access_token,
+                        refresh_token: token.refresh_token.or(Some(refresh_token)),
+                        expires_in: token.expires_in,
+                        id_token: token.id_token,
+                        r#type: "Bearer".to_owned(),
+                        user,
+                    };
+                    Self::store_tokens(&auth_body, remember_me);
+                    OIDC_SESSION.write().unwrap().auth_body = Some(auth_body);
+                    Ok(())
+                }
+                Err(err) => {
+                    if err.to_string().contains("invalid_grant") {
+                        Self::clear_stored_credentials();
+                    }
+                    Err(err)
+                }
+            }
+        } else {
+            let res: HbbHttpResponse<AuthBody> = OIDC_SESSION
+                .read()
+                .unwrap()
+                .client
+                .post(&endpoint)
+                .json(&HashMap::from([
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &refresh_token),
+                ]))
+                .send()?
+                .try_into()?;
+            match res {
+                HbbHttpResponse::<_>::Data(mut auth_body) => {
+                    if auth_body.refresh_token.is_none() {
+                        auth_body.refresh_token = Some(refresh_token);
+                    }
+                    Self::store_tokens(&auth_body, remember_me);
+                    OIDC_SESSION.write().unwrap().auth_body = Some(auth_body);
+                    Ok(())
+                }
+                HbbHttpResponse::<_>::Error(err) => {
+                    if err.contains("invalid_grant") {
+                        Self::clear_stored_credentials();
+                    }
+                    bail!(err)
+                }
+                _ => bail!("Invalid refresh response"),
+            }
+        }
+    }
+
+    fn clear_stored_credentials() {
+        LocalConfig::set_option("access_token".to_owned(), "".to_owned());
+        LocalConfig::set_option("refresh_token".to_owned(), "".to_owned());
+        LocalConfig::set_option("user_info".to_owned(), "".to_owned());
+        let mut session = OIDC_SESSION.write().unwrap();
+        session.access_token_expiry = None;
+        session.access_token = "".to_owned();
+        session.refresh_token = "".to_owned();
+        session.remember_me = false;
+        session.auth_body = None;
+        session.set_state(REQUESTING_ACCOUNT_AUTH, "".to_owned());
+    }
+
+    /// Returns a still-valid access token, transparently refreshing it first
+    /// if it is within `REFRESH_GRACE_SECS` of expiring (or already expired).
+    ///
+    /// Reads the in-memory token first so sessions that didn't opt into
+    /// "remember me" still work for the lifetime of the run, falling back to
+    /// `LocalConfig` for a token restored from a previous, remembered login.
+    pub fn valid_access_token() -> ResultType<String> {
+        let needs_refresh = match OIDC_SESSION.read().unwrap().access_token_expiry {
+            Some(expiry) => {
+                expiry.saturating_duration_since(Instant::now())
+                    < Duration::from_secs(REFRESH_GRACE_SECS)
+            }
+            None => false,
+        };
+        if needs_refresh {
+            Self::refresh()?;
+        }
+        let access_token = OIDC_SESSION.read().unwrap().access_token.clone();
+        let access_token = if access_token.is_empty() {
+            LocalConfig::get_option("access_token")
+        } else {
+            access_token
+        };
+        if access_token.is_empty() {
+            bail!("No access token available");
+        }
+        Ok(access_token)
+    }
 }